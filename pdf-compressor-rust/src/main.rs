@@ -3,9 +3,33 @@ use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time::Instant;
 
 use anyhow::{Context, Result};
-use clap::Parser;
-use lopdf::{Document, Object};
-use pdf_compressor_rust::process_image_object;
+use clap::{Parser, ValueEnum};
+use lopdf::Document;
+use pdf_compressor_rust::{
+    apply_rendered_image, dedupe_image_xobjects, is_image_xobject, prepare_image, recompress_streams,
+    recompress_streams_shared_dictionary, render_image, render_image_targeted, save_packed, Encoder,
+};
+use rayon::prelude::*;
+
+/// Which JPEG encoder backend to use, selectable from the CLI.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum EncoderArg {
+    /// The `image` crate's baseline JPEG writer. Always available.
+    #[default]
+    Image,
+    /// `mozjpeg`'s encoder (trellis quantization, progressive scans). Requires
+    /// the crate to be built with the `mozjpeg` feature.
+    Mozjpeg,
+}
+
+impl From<EncoderArg> for Encoder {
+    fn from(arg: EncoderArg) -> Self {
+        match arg {
+            EncoderArg::Image => Encoder::Image,
+            EncoderArg::Mozjpeg => Encoder::Mozjpeg,
+        }
+    }
+}
 
 /// Simple PDF compressor
 #[derive(Parser, Debug)]
@@ -21,6 +45,14 @@ struct Args {
     #[arg(long, default_value_t = 50)]
     quality: u8,
 
+    /// Instead of a single fixed quality, search several JPEG qualities per
+    /// image and keep the smallest one whose SSIM against the original
+    /// stays at or above this threshold (0.0-1.0). Overrides --quality and
+    /// disables --debug's per-image debug images, since the targeted search
+    /// discards every candidate but the one it keeps.
+    #[arg(long)]
+    min_ssim: Option<f64>,
+
     /// Max image dimension (longer side)
     #[arg(long, default_value_t = 1500)]
     max_dim: u32,
@@ -28,6 +60,34 @@ struct Args {
     /// Save debug images
     #[arg(long, default_value_t = false)]
     debug: bool,
+
+    /// JPEG encoder backend to use
+    #[arg(long, value_enum, default_value_t = EncoderArg::Image)]
+    encoder: EncoderArg,
+
+    /// Worker threads for image processing (0 = rayon default, one per core)
+    #[arg(long, default_value_t = 0)]
+    jobs: usize,
+
+    /// Also re-deflate non-image streams (content streams, fonts, ...) at
+    /// maximum compression
+    #[arg(long, default_value_t = false)]
+    recompress_streams: bool,
+
+    /// Pack indirect objects into compressed object streams and write a
+    /// compressed cross-reference stream instead of the classic xref table
+    /// (PDF 1.5+; requires a reader that supports it)
+    #[arg(long, default_value_t = false)]
+    pack_object_streams: bool,
+
+    /// Re-deflate non-image streams against one shared preset dictionary
+    /// instead of independently. NOT a standard PDF: output only reloads
+    /// correctly through tooling that knows this crate's private
+    /// `/DecodeParms /SharedDictionary` convention, so treat it as an
+    /// internal repack format rather than something to hand to a reader
+    /// or another PDF tool
+    #[arg(long, default_value_t = false)]
+    shared_dictionary: bool,
 }
 
 fn main() -> Result<()> {
@@ -35,8 +95,16 @@ fn main() -> Result<()> {
     let args = Args::parse();
 
     if args.debug {
-        std::fs::create_dir_all("debug_images")?;
-        println!("Debug mode enabled. Images will be saved to 'debug_images/' directory.");
+        if args.min_ssim.is_some() {
+            println!("--debug has no effect with --min-ssim: targeted search doesn't save per-candidate debug images.");
+        } else {
+            std::fs::create_dir_all("debug_images")?;
+            println!("Debug mode enabled. Images will be saved to 'debug_images/' directory.");
+        }
+    }
+
+    if let Some(min_ssim) = args.min_ssim {
+        println!("Targeted mode: searching JPEG qualities for SSIM >= {min_ssim} (--quality ignored).");
     }
 
     println!("Loading PDF: {:?}", args.input);
@@ -60,6 +128,17 @@ fn main() -> Result<()> {
     let images_processed = AtomicUsize::new(0);
     let original_size = std::fs::metadata(&args.input)?.len();
 
+    // Dedupe pass: generated PDFs often embed the same logo/header/background
+    // as many separate XObject streams. Collapse byte-identical copies onto a
+    // single canonical object before we spend CPU re-encoding each of them.
+    let deduped_ids = dedupe_image_xobjects(&mut doc);
+    if !deduped_ids.is_empty() {
+        println!(
+            "Deduplicated {} duplicate image XObject(s).",
+            deduped_ids.len()
+        );
+    }
+
     // Iterate over all objects to find XObject streams with Subtype = Image
     let object_ids: Vec<_> = doc.objects.keys().cloned().collect();
 
@@ -73,28 +152,7 @@ fn main() -> Result<()> {
             continue;
         }
 
-        let (is_image, smask_id) = if let Some(Object::Stream(stream)) = doc.objects.get(object_id)
-        {
-            if let Ok(subtype) = stream.dict.get(b"Subtype") {
-                if let Ok(name) = subtype.as_name() {
-                    if name == b"Image" {
-                        let smask = match stream.dict.get(b"SMask") {
-                            Ok(Object::Reference(id)) => Some(*id),
-                            _ => None,
-                        };
-                        (true, smask)
-                    } else {
-                        (false, None)
-                    }
-                } else {
-                    (false, None)
-                }
-            } else {
-                (false, None)
-            }
-        } else {
-            (false, None)
-        };
+        let (is_image, smask_id) = is_image_xobject(&doc, object_id);
 
         if is_image {
             if let Some(sid) = smask_id {
@@ -110,35 +168,18 @@ fn main() -> Result<()> {
     let mut processed_ids = std::collections::HashSet::new();
     let mut current_image_index = 0;
 
+    // Prepare phase: gather each image's bytes from `doc` sequentially (this
+    // needs `&mut Document` to resolve indirect Filter/DecodeParms refs), but
+    // do none of the CPU-heavy decode/resize/encode work yet.
+    let mut prepared = Vec::with_capacity(total_images as usize);
+
     for object_id in object_ids {
         if processed_ids.contains(&object_id) {
             continue;
         }
 
         // Check if it is an image and get smask info WITHOUT holding a borrow
-        let (is_image, smask_id) = {
-            if let Some(Object::Stream(stream)) = doc.objects.get(&object_id) {
-                if let Ok(subtype) = stream.dict.get(b"Subtype") {
-                    if let Ok(name) = subtype.as_name() {
-                        if name == b"Image" {
-                            let smask = match stream.dict.get(b"SMask") {
-                                Ok(Object::Reference(id)) => Some(*id),
-                                _ => None,
-                            };
-                            (true, smask)
-                        } else {
-                            (false, None)
-                        }
-                    } else {
-                        (false, None)
-                    }
-                } else {
-                    (false, None)
-                }
-            } else {
-                (false, None)
-            }
-        };
+        let (is_image, smask_id) = is_image_xobject(&doc, &object_id);
 
         if is_image {
             current_image_index += 1;
@@ -147,34 +188,89 @@ fn main() -> Result<()> {
                 processed_ids.insert(sid);
             }
 
-            match process_image_object(
-                &mut doc,
-                object_id,
-                args.quality,
-                args.max_dim,
-                args.debug,
-                current_image_index,
-            ) {
-                Ok(actions) => {
-                    println!(
-                        "Processing image {} of {} (ID: {}): {}",
-                        current_image_index, total_images, object_id.0, actions
-                    );
-                    images_processed.fetch_add(1, Ordering::Relaxed);
-                }
-                Err(e) => {
-                    println!(
-                        "Processing image {} of {} (ID: {}) - FAILED: {:?}",
-                        current_image_index, total_images, object_id.0, e
-                    );
-                }
+            match prepare_image(&mut doc, object_id) {
+                Ok(p) => prepared.push((current_image_index, p)),
+                Err(e) => println!(
+                    "Processing image {} of {} (ID: {}) - FAILED to prepare: {:?}",
+                    current_image_index, total_images, object_id.0, e
+                ),
             }
             processed_ids.insert(object_id);
         }
     }
 
+    // Render phase: the decode/resize/re-encode work runs in parallel on a
+    // rayon thread pool; `doc` is not touched again until every worker returns.
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(args.jobs)
+        .build()
+        .context("Failed to build thread pool")?;
+
+    let rendered: Vec<_> = pool.install(|| {
+        prepared
+            .into_par_iter()
+            .map(|(index, p)| {
+                let object_id = p.object_id;
+                let result = match args.min_ssim {
+                    Some(min_ssim) => {
+                        render_image_targeted(p, min_ssim, args.max_dim, args.encoder.into())
+                    }
+                    None => render_image(
+                        p,
+                        args.quality,
+                        args.max_dim,
+                        args.debug,
+                        index as u32,
+                        args.encoder.into(),
+                    ),
+                };
+                (index, object_id, result)
+            })
+            .collect()
+    });
+
+    // Apply phase: write the rendered results back into `doc` on the main thread.
+    for (index, object_id, result) in rendered {
+        match result {
+            Ok(r) => {
+                println!(
+                    "Processing image {} of {} (ID: {}): {}",
+                    index, total_images, object_id.0, r.actions
+                );
+                apply_rendered_image(&mut doc, r);
+                images_processed.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(e) => {
+                println!(
+                    "Processing image {} of {} (ID: {}) - FAILED: {:?}",
+                    index, total_images, object_id.0, e
+                );
+            }
+        }
+    }
+
+    if args.recompress_streams {
+        let count = recompress_streams(&mut doc);
+        println!("Recompressed {} non-image stream(s).", count);
+    }
+
+    if args.shared_dictionary {
+        let count = recompress_streams_shared_dictionary(&mut doc)
+            .context("Failed to recompress streams against a shared dictionary")?;
+        println!(
+            "Recompressed {} non-image stream(s) against a shared preset dictionary (internal repack format, not a standard PDF).",
+            count
+        );
+    }
+
     // Save
-    doc.save(&args.output).context("Failed to save PDF")?;
+    if args.pack_object_streams {
+        let packed = save_packed(&mut doc, &mut std::fs::File::create(&args.output)?)
+            .context("Failed to save packed PDF")?;
+        println!("Packed {} object(s) into object streams.", packed);
+    } else {
+        doc.save(&args.output).context("Failed to save PDF")?;
+    }
 
     let new_size = std::fs::metadata(&args.output)?.len();
     println!(
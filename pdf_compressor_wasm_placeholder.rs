@@ -1,46 +0,0 @@
-// This is a placeholder for the WASM-compatible Rust code.
-// The actual implementation requires a more complex setup with 'wasm-bindgen'
-// and a library that can handle PDF parsing and writing in a browser environment.
-// The CLI tool 'pdf-compressor-rust' demonstrated the core logic using 'lopdf' and 'image' crates.
-// For a browser version, you would wrap this logic in a function exposed via wasm-bindgen.
-
-/*
-// Example Cargo.toml for WASM
-[package]
-name = "pdf-compressor-wasm"
-version = "0.1.0"
-edition = "2021"
-crate-type = ["cdylib"]
-
-[dependencies]
-lopdf = "0.34"
-image = { version = "0.25", default-features = false, features = ["png", "jpeg"] }
-wasm-bindgen = "0.2"
-js-sys = "0.3"
-console_error_panic_hook = "0.1"
-*/
-
-/*
-// src/lib.rs
-use wasm_bindgen::prelude::*;
-use lopdf::Document;
-use image::ImageFormat;
-use std::io::Cursor;
-
-#[wasm_bindgen]
-pub fn compress_pdf(pdf_data: &[u8], quality: u8) -> Result<Vec<u8>, JsValue> {
-    console_error_panic_hook::set_once();
-
-    // Load Document from memory
-    let mut doc = Document::load_mem(pdf_data).map_err(|e| JsValue::from_str(&format!("Failed to load PDF: {}", e)))?;
-
-    // ... Implement the same iteration and compression logic as in main.rs ...
-    // Note: filesystem operations are not available, everything must be in-memory.
-
-    // Save to byte vector
-    let mut buffer = Vec::new();
-    doc.save_to(&mut buffer).map_err(|e| JsValue::from_str(&format!("Failed to save PDF: {}", e)))?;
-
-    Ok(buffer)
-}
-*/